@@ -0,0 +1,193 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A content-addressed `Blobstore` backed by the local filesystem.
+//!
+//! This is meant for tests and standalone tooling that want a
+//! dependency-light store for content manifests and other content blobs,
+//! without pulling in a full Blobstore backend stack.
+
+use std::fmt;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use async_trait::async_trait;
+use blobstore::Blobstore;
+use blobstore::BlobstoreGetData;
+use bytes::Bytes;
+use context::CoreContext;
+use mononoke_types::typed_hash::IdContext;
+use mononoke_types::BlobstoreBytes;
+use mononoke_types::BlobstoreKey;
+use tempfile::NamedTempFile;
+
+/// Number of leading hex nibbles of a blob's id used to shard the on-disk
+/// directory layout, so that a single directory never ends up holding an
+/// unbounded number of entries.
+const SHARD_NIBBLES: usize = 2;
+
+/// A content-addressed `Blobstore` backed by the local filesystem. Writes
+/// are atomic (temp file + fsync + rename); reads recompute the id from the
+/// loaded bytes via `C` and fail if it doesn't match the requested key.
+pub struct LocalFsBlobstore<C> {
+    base_path: PathBuf,
+    _context: PhantomData<C>,
+}
+
+impl<C> LocalFsBlobstore<C> {
+    pub fn new(base_path: PathBuf) -> Self {
+        Self {
+            base_path,
+            _context: PhantomData,
+        }
+    }
+
+    fn path_for_key(&self, key: &str) -> PathBuf {
+        // Blobstore keys are `<type>.<algo>.<hex digest>`; shard on the hex
+        // digest itself rather than the key as a whole, or every key of a
+        // given type would collapse into the same one or two directories.
+        let hex = key.rsplit('.').next().unwrap_or(key);
+        let shard = &hex[..hex.len().min(SHARD_NIBBLES)];
+        self.base_path.join(shard).join(key)
+    }
+}
+
+fn write_atomically(dir: &Path, path: &Path, data: &[u8]) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create directory {}", dir.display()))?;
+
+    let mut tmp = NamedTempFile::new_in(dir)
+        .with_context(|| format!("failed to create temp file in {}", dir.display()))?;
+    tmp.write_all(data)?;
+    tmp.as_file().sync_all()?;
+
+    match tmp.persist(path) {
+        Ok(_) => {
+            // The rename itself isn't durable until the directory entry is
+            // fsynced too, or a crash right after persist() can leave the
+            // file missing even though the rename reported success.
+            std::fs::File::open(dir)
+                .and_then(|dir_file| dir_file.sync_all())
+                .with_context(|| format!("failed to fsync directory {}", dir.display()))?;
+            Ok(())
+        }
+        // Another writer raced us to the same content-addressed path: the
+        // bytes are identical by construction, so this is fine.
+        Err(_) if path.exists() => Ok(()),
+        Err(e) => Err(e.error.into()),
+    }
+}
+
+#[async_trait]
+impl<C> Blobstore for LocalFsBlobstore<C>
+where
+    C: IdContext + Send + Sync + 'static,
+    C::Id: BlobstoreKey,
+{
+    async fn put(&self, _ctx: &CoreContext, key: String, value: BlobstoreBytes) -> Result<()> {
+        let path = self.path_for_key(&key);
+        if path.exists() {
+            return Ok(());
+        }
+
+        let dir = path
+            .parent()
+            .context("blob path unexpectedly has no parent directory")?
+            .to_path_buf();
+        write_atomically(&dir, &path, value.as_bytes())
+    }
+
+    async fn get(&self, _ctx: &CoreContext, key: &str) -> Result<Option<BlobstoreGetData>> {
+        let path = self.path_for_key(key);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("failed to read blob from local filesystem"),
+        };
+
+        let id = C::id_from_data(&data);
+        if id.blobstore_key() != key {
+            bail!(
+                "corrupt blob at {}: recomputed key {} does not match requested key {}",
+                path.display(),
+                id.blobstore_key(),
+                key,
+            );
+        }
+
+        Ok(Some(BlobstoreBytes::from_bytes(Bytes::from(data)).into()))
+    }
+}
+
+impl<C> fmt::Display for LocalFsBlobstore<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LocalFsBlobstore({})", self.base_path.display())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hash;
+    use std::hash::Hasher;
+
+    use context::CoreContext;
+    use fbinit::FacebookInit;
+
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    struct TestId(u64);
+
+    impl BlobstoreKey for TestId {
+        fn blobstore_key(&self) -> String {
+            format!("test.blake2.{:016x}", self.0)
+        }
+    }
+
+    struct TestIdContext;
+
+    impl IdContext for TestIdContext {
+        type Id = TestId;
+
+        fn id_from_data(data: &[u8]) -> TestId {
+            let mut hasher = DefaultHasher::new();
+            data.hash(&mut hasher);
+            TestId(hasher.finish())
+        }
+    }
+
+    #[fbinit::test]
+    async fn put_then_get_round_trips(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let dir = tempfile::tempdir()?;
+        let store = LocalFsBlobstore::<TestIdContext>::new(dir.path().to_path_buf());
+
+        let data = BlobstoreBytes::from_bytes(Bytes::from_static(b"hello world"));
+        let key = TestIdContext::id_from_data(data.as_bytes()).blobstore_key();
+
+        store.put(&ctx, key.clone(), data.clone()).await?;
+        let got = store.get(&ctx, &key).await?.expect("blob should be present");
+        assert_eq!(got.into_bytes(), data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shards_by_hex_digest_not_type_prefix() {
+        let store = LocalFsBlobstore::<TestIdContext>::new(PathBuf::from("/tmp/local_fs_blobstore"));
+        let key = TestId(0xabcd_0000_0000_0000).blobstore_key();
+        let path = store.path_for_key(&key);
+
+        assert_eq!(path.parent().unwrap().file_name().unwrap(), "ab");
+    }
+}
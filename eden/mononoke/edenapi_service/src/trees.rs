@@ -0,0 +1,260 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Server-side support for the `gettreepack` wire protocol command.
+//!
+//! A naive implementation of `gettreepack` would just return every tree
+//! reachable from `mfnodes`. `TreeRequest::basemfnodes` lets the client say
+//! which manifests it already has, so the server can skip re-sending
+//! subtrees that are unchanged relative to those bases.
+
+use std::collections::HashSet;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use edenapi_types::DataEntry;
+use edenapi_types::DataResponse;
+use edenapi_types::TreeRequest;
+use types::Key;
+
+/// Knows how to load a single tree node's raw content and the keys of the
+/// subdirectories it references, so `select_trees_for_gettreepack` can walk
+/// a manifest without caring how trees are actually stored (Mononoke's
+/// `ContentManifest`, a bonsai tree manifest, or anything else).
+#[async_trait]
+pub trait TreeLoader: Send + Sync {
+    async fn load_tree(&self, key: &Key) -> Result<(Bytes, Vec<Key>)>;
+}
+
+/// Computes the minimal set of tree nodes the client needs for `request`:
+/// everything reachable from `request.mfnodes` that isn't already reachable
+/// from `request.basemfnodes`, honoring `request.depth` if set.
+///
+/// Seeds a "known" set from the base manifests, then walks each requested
+/// manifest top-down, pruning any subtree whose key is already known rather
+/// than re-reading and re-sending it.
+pub async fn select_trees_for_gettreepack(
+    loader: &impl TreeLoader,
+    request: &TreeRequest,
+) -> Result<DataResponse> {
+    let mut known = HashSet::new();
+    for basemfnode in &request.basemfnodes {
+        let root = Key::new(request.rootdir.clone(), basemfnode.clone());
+        collect_known(loader, root, request.depth, &mut known).await?;
+    }
+
+    // Reused (and added to) across all requested manifests, so a subtree
+    // shared by two requested `mfnodes` is only loaded and sent once.
+    let mut seen = known;
+    let mut entries = Vec::new();
+    for mfnode in &request.mfnodes {
+        let root = Key::new(request.rootdir.clone(), mfnode.clone());
+        collect_delta(loader, root, request.depth, &mut seen, &mut entries).await?;
+    }
+
+    Ok(DataResponse::new(entries))
+}
+
+/// Walks a base manifest, recording the key of every tree node reachable
+/// from it. These are the subtrees the client is assumed to already have.
+fn collect_known<'a>(
+    loader: &'a impl TreeLoader,
+    key: Key,
+    depth: Option<usize>,
+    known: &'a mut HashSet<Key>,
+) -> futures::future::BoxFuture<'a, Result<()>> {
+    use futures::FutureExt;
+
+    async move {
+        if !known.insert(key.clone()) {
+            // Already visited this node via another base manifest.
+            return Ok(());
+        }
+
+        if depth == Some(0) {
+            return Ok(());
+        }
+
+        let (_data, children) = loader.load_tree(&key).await?;
+        let next_depth = depth.map(|depth| depth - 1);
+
+        // `known` is behind a single `&mut` reference, so children are
+        // visited one at a time rather than concurrently; each child's
+        // visited-check depends on the siblings already walked, so they
+        // can't be fetched independently.
+        for child in children {
+            collect_known(loader, child, next_depth, known).await?;
+        }
+
+        Ok(())
+    }
+    .boxed()
+}
+
+/// Walks a requested manifest top-down, pruning any subtree whose key is
+/// already in `seen` (either because the client already has it via a base
+/// manifest, or because it was already emitted while walking an earlier
+/// requested manifest) and collecting the rest into `entries`.
+fn collect_delta<'a>(
+    loader: &'a impl TreeLoader,
+    key: Key,
+    depth: Option<usize>,
+    seen: &'a mut HashSet<Key>,
+    entries: &'a mut Vec<DataEntry>,
+) -> futures::future::BoxFuture<'a, Result<()>> {
+    use futures::FutureExt;
+
+    async move {
+        if !seen.insert(key.clone()) {
+            return Ok(());
+        }
+
+        let (data, children) = loader.load_tree(&key).await?;
+        entries.push(DataEntry::new(key.clone(), data));
+
+        if depth == Some(0) {
+            return Ok(());
+        }
+
+        let next_depth = depth.map(|depth| depth - 1);
+
+        // As with `collect_known`, children share `seen` and so are walked
+        // sequentially rather than concurrently.
+        for child in children {
+            collect_delta(loader, child, next_depth, seen, entries).await?;
+        }
+
+        Ok(())
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use types::RepoPathBuf;
+
+    use super::*;
+
+    /// An in-memory `TreeLoader` backed by a fixed key -> (data, children) map,
+    /// with a call counter so tests can assert a tree is only loaded once.
+    #[derive(Default)]
+    struct FakeTreeLoader {
+        trees: HashMap<Key, (Bytes, Vec<Key>)>,
+        loads: std::sync::atomic::AtomicUsize,
+    }
+
+    impl FakeTreeLoader {
+        fn insert(&mut self, key: Key, data: &'static [u8], children: Vec<Key>) {
+            self.trees.insert(key, (Bytes::from_static(data), children));
+        }
+
+        fn load_count(&self) -> usize {
+            self.loads.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl TreeLoader for FakeTreeLoader {
+        async fn load_tree(&self, key: &Key) -> Result<(Bytes, Vec<Key>)> {
+            self.loads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.trees
+                .get(key)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("no such tree: {:?}", key))
+        }
+    }
+
+    fn key(name: &str) -> Key {
+        Key::new(RepoPathBuf::new(), hgid(name))
+    }
+
+    fn hgid(name: &str) -> types::HgId {
+        let mut bytes = [0u8; 20];
+        let name = name.as_bytes();
+        bytes[..name.len().min(20)].copy_from_slice(&name[..name.len().min(20)]);
+        types::HgId::from_byte_array(bytes)
+    }
+
+    fn request(mfnodes: Vec<Key>, basemfnodes: Vec<Key>, depth: Option<usize>) -> TreeRequest {
+        TreeRequest::new(
+            RepoPathBuf::new(),
+            mfnodes.into_iter().map(|k| k.hgid).collect(),
+            basemfnodes.into_iter().map(|k| k.hgid).collect(),
+            depth,
+        )
+    }
+
+    #[tokio::test]
+    async fn depth_zero_returns_only_the_root() -> Result<()> {
+        let root = key("root");
+        let child = key("child");
+
+        let mut loader = FakeTreeLoader::default();
+        loader.insert(root.clone(), b"root", vec![child.clone()]);
+        loader.insert(child.clone(), b"child", vec![]);
+
+        let response =
+            select_trees_for_gettreepack(&loader, &request(vec![root.clone()], vec![], Some(0)))
+                .await?;
+
+        assert_eq!(response.entries.len(), 1);
+        assert_eq!(response.entries[0].key, root);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shared_subtree_across_mfnodes_is_loaded_once() -> Result<()> {
+        let shared = key("shared");
+        let root_a = key("root_a");
+        let root_b = key("root_b");
+
+        let mut loader = FakeTreeLoader::default();
+        loader.insert(root_a.clone(), b"root_a", vec![shared.clone()]);
+        loader.insert(root_b.clone(), b"root_b", vec![shared.clone()]);
+        loader.insert(shared.clone(), b"shared", vec![]);
+
+        let response = select_trees_for_gettreepack(
+            &loader,
+            &request(vec![root_a.clone(), root_b.clone()], vec![], None),
+        )
+        .await?;
+
+        // Three distinct trees, even though `shared` is reachable from both roots.
+        assert_eq!(response.entries.len(), 3);
+        assert_eq!(loader.load_count(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn basemfnode_subtree_is_pruned() -> Result<()> {
+        let shared = key("shared");
+        let base = key("base");
+        let root = key("root");
+
+        let mut loader = FakeTreeLoader::default();
+        loader.insert(base.clone(), b"base", vec![shared.clone()]);
+        loader.insert(root.clone(), b"root", vec![shared.clone()]);
+        loader.insert(shared.clone(), b"shared", vec![]);
+
+        let response = select_trees_for_gettreepack(
+            &loader,
+            &request(vec![root.clone()], vec![base.clone()], None),
+        )
+        .await?;
+
+        // `shared` came from the base manifest, so only `root` is sent.
+        assert_eq!(response.entries.len(), 1);
+        assert_eq!(response.entries[0].key, root);
+
+        Ok(())
+    }
+}
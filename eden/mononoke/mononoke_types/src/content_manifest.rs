@@ -5,9 +5,19 @@
  * GNU General Public License version 2.
  */
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use anyhow::Context;
 use anyhow::Result;
 use blobstore::Blobstore;
+use blobstore::Loadable;
+use bytes::Bytes;
 use context::CoreContext;
+use futures::future::try_join;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use futures::stream;
 use futures::stream::BoxStream;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
@@ -15,6 +25,7 @@ use futures::stream::TryStreamExt;
 use crate::blob::Blob;
 use crate::blob::BlobstoreValue;
 use crate::blob::ContentManifestBlob;
+use crate::sharded_map_v2::Rollup;
 use crate::sharded_map_v2::ShardedMapV2Node;
 use crate::sharded_map_v2::ShardedMapV2Value;
 use crate::thrift;
@@ -25,6 +36,7 @@ use crate::typed_hash::IdContext;
 use crate::typed_hash::ShardedMapV2NodeContentManifestContext;
 use crate::typed_hash::ShardedMapV2NodeContentManifestId;
 use crate::FileType;
+use crate::MPath;
 use crate::MPathElement;
 use crate::ThriftConvert;
 
@@ -38,6 +50,9 @@ pub struct ContentManifestFile {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ContentManifestDirectory {
     pub id: ContentManifestId,
+    /// Rollup of the subtree `id` points to, so summaries don't need to
+    /// fetch it.
+    pub rollup: ContentManifestRollup,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -89,6 +104,171 @@ impl ContentManifest {
             .and_then(|(k, v)| async move { anyhow::Ok((MPathElement::from_smallvec(k)?, v)) })
             .boxed()
     }
+
+    /// Diff this manifest against `other`, returning the set of changes
+    /// between them keyed by path. Subtrees whose `ContentManifestId` is
+    /// identical on both sides are never read from the blobstore, which
+    /// keeps this cheap even for large trees that mostly share structure.
+    pub fn diff<'a>(
+        self,
+        ctx: &'a CoreContext,
+        blobstore: &'a impl Blobstore,
+        other: Self,
+    ) -> BoxStream<'a, Result<(MPath, ContentManifestDiffEntry)>> {
+        self.diff_prefix(ctx, blobstore, other, &[])
+    }
+
+    /// Like `diff`, but only considers top-level subentries whose raw key
+    /// starts with `prefix`.
+    pub fn diff_prefix<'a>(
+        self,
+        ctx: &'a CoreContext,
+        blobstore: &'a impl Blobstore,
+        other: Self,
+        prefix: &'a [u8],
+    ) -> BoxStream<'a, Result<(MPath, ContentManifestDiffEntry)>> {
+        diff_content_manifests(ctx, blobstore, None, self, other, prefix)
+            .map(|diff| stream::iter(diff.into_iter().map(Ok)))
+            .try_flatten_stream()
+            .boxed()
+    }
+
+    /// Total size, file count and subdirectory count of this manifest.
+    pub async fn summary(
+        &self,
+        ctx: &CoreContext,
+        blobstore: &impl Blobstore,
+    ) -> Result<ContentManifestRollup> {
+        self.subentries.rollup_data(ctx, blobstore).await
+    }
+}
+
+/// A single change between two `ContentManifest`s at a given path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentManifestDiffEntry {
+    Added(ContentManifestEntry),
+    Removed(ContentManifestEntry),
+    Modified {
+        from: ContentManifestEntry,
+        to: ContentManifestEntry,
+    },
+}
+
+fn join(base: &Option<MPath>, element: &MPathElement) -> MPath {
+    match base {
+        Some(base) => base.join_element(Some(element)),
+        None => MPath::from(element.clone()),
+    }
+}
+
+fn diff_content_manifests<'a>(
+    ctx: &'a CoreContext,
+    blobstore: &'a impl Blobstore,
+    base: Option<MPath>,
+    from: ContentManifest,
+    to: ContentManifest,
+    prefix: &'a [u8],
+) -> BoxFuture<'a, Result<Vec<(MPath, ContentManifestDiffEntry)>>> {
+    async move {
+        let (from_entries, to_entries) = try_join(
+            from.into_prefix_subentries(ctx, blobstore, prefix)
+                .try_collect::<BTreeMap<_, _>>(),
+            to.into_prefix_subentries(ctx, blobstore, prefix)
+                .try_collect::<BTreeMap<_, _>>(),
+        )
+        .await?;
+
+        let keys: BTreeSet<_> = from_entries.keys().chain(to_entries.keys()).collect();
+
+        let mut diff = Vec::new();
+        for key in keys {
+            let path = join(&base, key);
+            match (from_entries.get(key), to_entries.get(key)) {
+                (Some(from_entry), None) => {
+                    diff.push((path, ContentManifestDiffEntry::Removed(from_entry.clone())));
+                }
+                (None, Some(to_entry)) => {
+                    diff.push((path, ContentManifestDiffEntry::Added(to_entry.clone())));
+                }
+                (Some(from_entry), Some(to_entry)) => {
+                    diff.extend(diff_entries(ctx, blobstore, path, from_entry, to_entry).await?);
+                }
+                (None, None) => unreachable!("key came from one of the two maps"),
+            }
+        }
+
+        Ok(diff)
+    }
+    .boxed()
+}
+
+fn diff_entries<'a>(
+    ctx: &'a CoreContext,
+    blobstore: &'a impl Blobstore,
+    path: MPath,
+    from: &'a ContentManifestEntry,
+    to: &'a ContentManifestEntry,
+) -> BoxFuture<'a, Result<Vec<(MPath, ContentManifestDiffEntry)>>> {
+    async move {
+        match (from, to) {
+            (ContentManifestEntry::File(from_file), ContentManifestEntry::File(to_file)) => {
+                if from_file == to_file {
+                    Ok(Vec::new())
+                } else {
+                    Ok(vec![(
+                        path,
+                        ContentManifestDiffEntry::Modified {
+                            from: from.clone(),
+                            to: to.clone(),
+                        },
+                    )])
+                }
+            }
+            (
+                ContentManifestEntry::Directory(from_dir),
+                ContentManifestEntry::Directory(to_dir),
+            ) => {
+                // Same id means the same content-addressed subtree: prune
+                // it rather than descending into it.
+                if from_dir.id == to_dir.id {
+                    return Ok(Vec::new());
+                }
+
+                let (from_manifest, to_manifest) = try_join(
+                    fetch_content_manifest(ctx, blobstore, from_dir.id),
+                    fetch_content_manifest(ctx, blobstore, to_dir.id),
+                )
+                .await?;
+
+                diff_content_manifests(
+                    ctx,
+                    blobstore,
+                    Some(path),
+                    from_manifest,
+                    to_manifest,
+                    &[],
+                )
+                .await
+            }
+            // A file became a directory (or vice versa) at the same path:
+            // report it as the old entry disappearing and the new one
+            // appearing, rather than inventing a "type change" variant.
+            _ => Ok(vec![
+                (path.clone(), ContentManifestDiffEntry::Removed(from.clone())),
+                (path, ContentManifestDiffEntry::Added(to.clone())),
+            ]),
+        }
+    }
+    .boxed()
+}
+
+async fn fetch_content_manifest(
+    ctx: &CoreContext,
+    blobstore: &impl Blobstore,
+    id: ContentManifestId,
+) -> Result<ContentManifest> {
+    let blob = id.load(ctx, blobstore).await?;
+    Ok(blob)
 }
 
 impl ThriftConvert for ContentManifestFile {
@@ -119,12 +299,14 @@ impl ThriftConvert for ContentManifestDirectory {
     fn from_thrift(t: Self::Thrift) -> Result<Self> {
         Ok(Self {
             id: ThriftConvert::from_thrift(t.id)?,
+            rollup: ContentManifestRollup::from_thrift(t.rollup)?,
         })
     }
 
     fn into_thrift(self) -> Self::Thrift {
         Self::Thrift {
             id: self.id.into_thrift(),
+            rollup: self.rollup.into_thrift(),
         }
     }
 }
@@ -168,24 +350,331 @@ impl ThriftConvert for ContentManifest {
     }
 }
 
+/// At or below this size, small manifests are always stored raw rather than
+/// paying for a compression attempt.
+///
+/// TODO: expose this as a tunable once
+/// `content_manifest_zstd_compression_threshold` is registered; for now it's
+/// a plain constant so this compiles against the real tunables registry.
+const ZSTD_COMPRESSION_THRESHOLD: usize = 256;
+
+/// Leading byte of a `ContentManifest` blob, distinguishing whether the
+/// remainder is the raw Thrift encoding or a zstd-compressed copy of it.
+#[repr(u8)]
+enum ContentManifestBlobFormat {
+    Raw = 0,
+    Zstd = 1,
+}
+
+impl ContentManifestBlobFormat {
+    fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(Self::Raw),
+            1 => Ok(Self::Zstd),
+            byte => Err(anyhow::anyhow!(
+                "invalid ContentManifest blob format byte: {}",
+                byte
+            )),
+        }
+    }
+}
+
+/// Picks the on-disk format for `data` and returns its header byte plus
+/// payload: raw at or below `ZSTD_COMPRESSION_THRESHOLD`, or if compression
+/// doesn't actually make it smaller; zstd otherwise.
+fn compress_for_blob(data: &[u8]) -> (ContentManifestBlobFormat, Vec<u8>) {
+    if data.len() > ZSTD_COMPRESSION_THRESHOLD {
+        if let Ok(compressed) = zstd::stream::encode_all(data, 0) {
+            if compressed.len() < data.len() {
+                return (ContentManifestBlobFormat::Zstd, compressed);
+            }
+        }
+    }
+    (ContentManifestBlobFormat::Raw, data.to_vec())
+}
+
+fn decompress_from_blob(format: ContentManifestBlobFormat, payload: &[u8]) -> Result<Vec<u8>> {
+    match format {
+        ContentManifestBlobFormat::Raw => Ok(payload.to_vec()),
+        ContentManifestBlobFormat::Zstd => {
+            zstd::stream::decode_all(payload).context("failed to decompress ContentManifest blob")
+        }
+    }
+}
+
 impl BlobstoreValue for ContentManifest {
     type Key = ContentManifestId;
 
     fn into_blob(self) -> ContentManifestBlob {
         let data = self.into_bytes();
+        // The id is always derived from the uncompressed bytes, so it stays
+        // stable regardless of whether this blob ends up stored raw or
+        // compressed, and dedup against existing ids keeps working.
         let id = ContentManifestIdContext::id_from_data(&data);
-        Blob::new(id, data)
+        let (format, payload) = compress_for_blob(&data);
+
+        let mut blob_data = Vec::with_capacity(payload.len() + 1);
+        blob_data.push(format as u8);
+        blob_data.extend(payload);
+
+        Blob::new(id, Bytes::from(blob_data))
     }
 
     fn from_blob(blob: Blob<Self::Key>) -> Result<Self> {
-        Self::from_bytes(blob.data())
+        let data = blob.data();
+        let (format, payload) = data
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("ContentManifest blob is empty"))?;
+
+        let data = decompress_from_blob(ContentManifestBlobFormat::from_byte(*format)?, payload)?;
+        Self::from_bytes(&data)
+    }
+}
+
+/// Bottom-up aggregate of a `ContentManifest` subtree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ContentManifestRollup {
+    pub total_size: u64,
+    pub file_count: u64,
+    pub subdir_count: u64,
+}
+
+impl ThriftConvert for ContentManifestRollup {
+    const NAME: &'static str = "ContentManifestRollup";
+    type Thrift = thrift::content_manifest::ContentManifestRollup;
+
+    fn from_thrift(t: Self::Thrift) -> Result<Self> {
+        Ok(Self {
+            total_size: t.total_size as u64,
+            file_count: t.file_count as u64,
+            subdir_count: t.subdir_count as u64,
+        })
+    }
+
+    fn into_thrift(self) -> Self::Thrift {
+        Self::Thrift {
+            total_size: self.total_size as i64,
+            file_count: self.file_count as i64,
+            subdir_count: self.subdir_count as i64,
+        }
+    }
+}
+
+impl Rollup<ContentManifestEntry> for ContentManifestRollup {
+    fn rollup(entry: Option<&ContentManifestEntry>, child_rollups: Vec<Self>) -> Self {
+        let mut rollup = child_rollups
+            .into_iter()
+            .fold(Self::default(), |acc, child| Self {
+                total_size: acc.total_size + child.total_size,
+                file_count: acc.file_count + child.file_count,
+                subdir_count: acc.subdir_count + child.subdir_count,
+            });
+
+        match entry {
+            Some(ContentManifestEntry::File(file)) => {
+                rollup.total_size += file.size;
+                rollup.file_count += 1;
+            }
+            Some(ContentManifestEntry::Directory(dir)) => {
+                rollup.total_size += dir.rollup.total_size;
+                rollup.file_count += dir.rollup.file_count;
+                // +1 for the directory itself, plus everything beneath it.
+                rollup.subdir_count += dir.rollup.subdir_count + 1;
+            }
+            None => {}
+        }
+
+        rollup
     }
 }
 
 impl ShardedMapV2Value for ContentManifestEntry {
     type NodeId = ShardedMapV2NodeContentManifestId;
     type Context = ShardedMapV2NodeContentManifestContext;
-    type RollupData = ();
+    type RollupData = ContentManifestRollup;
 
     const WEIGHT_LIMIT: usize = 2000;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rollup_includes_nested_directory_totals() {
+        // Everything beneath the "child" directory: one 7 byte file, plus one
+        // grandchild subdirectory, already folded in when it was last written.
+        let grandchild_rollup = ContentManifestRollup {
+            total_size: 7,
+            file_count: 1,
+            subdir_count: 1,
+        };
+        let child = ContentManifestEntry::Directory(ContentManifestDirectory {
+            id: ContentManifestIdContext::id_from_data(b"child"),
+            rollup: grandchild_rollup,
+        });
+
+        // Folding the "child" directory entry itself must carry forward
+        // everything beneath it, plus count "child" as one more subdir.
+        let child_rollup = ContentManifestRollup::rollup(Some(&child), vec![]);
+        assert_eq!(
+            child_rollup,
+            ContentManifestRollup {
+                total_size: 7,
+                file_count: 1,
+                subdir_count: 2,
+            }
+        );
+
+        // And folding up to the root must not lose anything either.
+        let root_rollup = ContentManifestRollup::rollup(None, vec![child_rollup]);
+        assert_eq!(root_rollup, child_rollup);
+    }
+
+    #[test]
+    fn small_payload_is_stored_raw() {
+        let data = b"a tiny manifest";
+        let (format, payload) = compress_for_blob(data);
+        assert!(matches!(format, ContentManifestBlobFormat::Raw));
+        assert_eq!(payload, data);
+    }
+
+    #[test]
+    fn compressible_payload_above_threshold_is_stored_zstd() {
+        let data = vec![b'x'; ZSTD_COMPRESSION_THRESHOLD + 1];
+        let (format, payload) = compress_for_blob(&data);
+        assert!(matches!(format, ContentManifestBlobFormat::Zstd));
+        assert_eq!(decompress_from_blob(format, &payload).unwrap(), data);
+    }
+
+    #[test]
+    fn incompressible_payload_above_threshold_falls_back_to_raw() {
+        // Already-compressed-looking pseudo-random bytes: zstd can't shrink
+        // these, so despite being above the threshold this must still be
+        // stored raw, since the compressed copy is no smaller.
+        let mut state: u64 = 0x1234_5678_9abc_def0;
+        let data: Vec<u8> = (0..=ZSTD_COMPRESSION_THRESHOLD)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let (format, payload) = compress_for_blob(&data);
+        assert!(matches!(format, ContentManifestBlobFormat::Raw));
+        assert_eq!(payload, data);
+    }
+
+    #[test]
+    fn raw_round_trips_through_decompress() {
+        let data = b"round trip me";
+        assert_eq!(
+            decompress_from_blob(ContentManifestBlobFormat::Raw, data).unwrap(),
+            data
+        );
+    }
+
+    /// A `Blobstore` that panics if touched, for asserting that `diff`
+    /// doesn't read the blobstore for entries it can resolve without it.
+    struct PanicBlobstore;
+
+    #[async_trait::async_trait]
+    impl Blobstore for PanicBlobstore {
+        async fn get(
+            &self,
+            _ctx: &CoreContext,
+            _key: &str,
+        ) -> Result<Option<blobstore::BlobstoreGetData>> {
+            panic!("diff should not read the blobstore for this entry");
+        }
+
+        async fn put(
+            &self,
+            _ctx: &CoreContext,
+            _key: String,
+            _value: crate::BlobstoreBytes,
+        ) -> Result<()> {
+            panic!("diff should not write the blobstore");
+        }
+    }
+
+    fn test_file(content: &[u8], size: u64) -> ContentManifestEntry {
+        ContentManifestEntry::File(ContentManifestFile {
+            content_id: crate::typed_hash::ContentIdContext::id_from_data(content),
+            file_type: FileType::Regular,
+            size,
+        })
+    }
+
+    fn test_directory(seed: &[u8]) -> ContentManifestEntry {
+        ContentManifestEntry::Directory(ContentManifestDirectory {
+            id: ContentManifestIdContext::id_from_data(seed),
+            rollup: ContentManifestRollup::default(),
+        })
+    }
+
+    #[fbinit::test]
+    async fn diff_identical_files_is_unchanged(fb: fbinit::FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let path = MPath::new(b"a").unwrap();
+        let file = test_file(b"hello", 5);
+
+        let diff = diff_entries(&ctx, &PanicBlobstore, path, &file, &file).await?;
+        assert_eq!(diff, Vec::new());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn diff_modified_file_reports_from_and_to(fb: fbinit::FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let path = MPath::new(b"a").unwrap();
+        let from = test_file(b"hello", 5);
+        let to = test_file(b"hello!", 6);
+
+        let diff = diff_entries(&ctx, &PanicBlobstore, path.clone(), &from, &to).await?;
+        assert_eq!(
+            diff,
+            vec![(
+                path,
+                ContentManifestDiffEntry::Modified {
+                    from: from.clone(),
+                    to: to.clone(),
+                }
+            )]
+        );
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn diff_file_to_directory_is_removed_and_added(fb: fbinit::FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let path = MPath::new(b"a").unwrap();
+        let from = test_file(b"hello", 5);
+        let to = test_directory(b"a-as-dir");
+
+        let diff = diff_entries(&ctx, &PanicBlobstore, path.clone(), &from, &to).await?;
+        assert_eq!(
+            diff,
+            vec![
+                (path.clone(), ContentManifestDiffEntry::Removed(from)),
+                (path, ContentManifestDiffEntry::Added(to)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn diff_same_directory_id_is_pruned_without_reading_blobstore(
+        fb: fbinit::FacebookInit,
+    ) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let path = MPath::new(b"a").unwrap();
+        let dir = test_directory(b"unchanged");
+
+        // This would panic if `diff_entries` fetched either subtree.
+        let diff = diff_entries(&ctx, &PanicBlobstore, path, &dir, &dir).await?;
+        assert_eq!(diff, Vec::new());
+        Ok(())
+    }
+}